@@ -1,53 +1,149 @@
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
 use std::future::Future;
 use std::pin::Pin;
 use std::rc::Rc;
 use std::task::{Context, Poll, Waker};
 
-use waker::fake_waker;
+use scheduler::{EventRegistry, WaitEvent};
+use waker::flag_waker;
 
+mod recorder;
+mod scheduler;
 mod waker;
 
-pub fn run<Input, Output, Fut>(
-    blueprint: impl FnOnce(Handle<Input, Output>) -> Fut,
-) -> impl Iterator<Item = Event<Input, Output, Fut::Output>>
+pub use recorder::{replay, Recorder, ReplayError, Transcript};
+pub use scheduler::{EventHandle, Scheduler, TaskId};
+
+pub fn run<Input, Output, Tag, Fut>(
+    blueprint: impl FnOnce(Handle<Input, Output, Tag>) -> Fut,
+) -> impl Iterator<Item = (TaskId, Event<Input, Output, Fut::Output, Tag>)>
 where
     Input: 'static,
     Output: 'static,
+    Tag: 'static,
     Fut: Future + 'static,
     Fut::Output: 'static,
 {
-    let inner = SharedCell::new();
+    let inner = RequestQueue::new();
+    let spawner = Rc::new(RefCell::new(Spawner::new()));
 
     let handle = Handle {
         inner: inner.clone(),
+        task_id: TaskId::default(),
+        blocked: Rc::new(Cell::new(false)),
+        events: Rc::new(RefCell::new(EventRegistry::default())),
+        spawner: spawner.clone(),
     };
 
     let future = blueprint(handle);
 
-    Executor::new(future, inner)
+    Executor::new(future, inner, spawner)
 }
 
-pub struct Handle<Input, Output> {
-    inner: SharedCell<InnerEvent<Input, Output>>,
+pub struct Handle<Input, Output, Tag = ()> {
+    inner: RequestQueue<Input, Output, Tag>,
+    task_id: TaskId,
+    blocked: Rc<Cell<bool>>,
+    events: Rc<RefCell<EventRegistry>>,
+    spawner: SpawnerHandle<Input, Output, Tag>,
 }
 
-impl<Input, Output> Handle<Input, Output> {
-    pub fn want_input(&self) -> impl Future<Output = Input> {
+impl<Input, Output, Tag> Handle<Input, Output, Tag> {
+    /// Requests an untagged input. Equivalent to `want_input_tagged(Tag::default())`.
+    pub fn want_input(&self) -> impl Future<Output = Input>
+    where
+        Tag: Default,
+    {
+        self.want_input_tagged(Tag::default())
+    }
+
+    /// Requests an input, tagged so the driver can tell several outstanding requests
+    /// apart by matching on `tag` in the resulting `Event::Input`.
+    pub fn want_input_tagged(&self, tag: Tag) -> impl Future<Output = Input> {
         let holder = SharedCell::new();
         let responder = Responder::new(holder.clone());
 
-        let event = InnerEvent::Input(responder);
-        self.inner.0.set(Some(event));
+        self.inner.push(InnerEvent::Input { tag, responder });
 
         WantInput::new(holder)
     }
 
+    /// Issues several input requests at once and resolves with an answered one: on each
+    /// poll, the first arm in `tags` order whose request has been answered wins. The other
+    /// requests' [`Responder`]s are marked cancelled so a later call to
+    /// `provide` on them is silently dropped.
+    pub fn select_input<const N: usize>(&self, tags: [Tag; N]) -> impl Future<Output = (Tag, Input)>
+    where
+        Tag: Clone + Unpin,
+    {
+        let arms: Vec<_> = tags
+            .into_iter()
+            .map(|tag| {
+                let holder = SharedCell::new();
+                let cancelled = Rc::new(Cell::new(false));
+                let responder = Responder::new_cancellable(holder.clone(), cancelled.clone());
+
+                self.inner.push(InnerEvent::Input {
+                    tag: tag.clone(),
+                    responder,
+                });
+
+                (tag, holder, cancelled)
+            })
+            .collect();
+
+        SelectInput { arms }
+    }
+
     pub fn provide_output(&self, output: Output) -> impl Future<Output = ()> {
         let event = InnerEvent::Output(output);
-        self.inner.0.set(Some(event));
+        self.inner.push(event);
         Pause::default()
     }
+
+    /// Parks this blueprint until `handle` is fired via [`Scheduler::notify`].
+    ///
+    /// Only meaningful for blueprints added to a [`Scheduler`] via
+    /// [`Scheduler::add_blueprint`] and the children they spawn; the returned future resolves as soon as the
+    /// scheduler's next `step()` sees the task unparked.
+    pub fn wait_event(&self, handle: EventHandle) -> impl Future<Output = ()> {
+        WaitEvent::new(self.task_id, handle, self.events.clone(), self.blocked.clone())
+    }
+}
+
+impl<Input: 'static, Output: 'static, Tag: 'static> Handle<Input, Output, Tag> {
+    /// Launches `child` as a nested sub-blueprint. Its own `want_input`/`provide_output`
+    /// calls interleave into this blueprint's event stream as ordinary `Event::Input`/
+    /// `Event::Output` items tagged with the child's own [`TaskId`]; the returned future
+    /// resolves to the child's return value once it completes.
+    ///
+    /// Works the same way whether `self` came from [`run`] or from a blueprint added to a
+    /// [`Scheduler`] via `Scheduler::add_blueprint`: either one polls the spawned tree
+    /// round-robin alongside its own task(s) and routes the child's events in by its
+    /// `TaskId`.
+    pub fn spawn<ChildRet, Fut>(
+        &self,
+        child: impl FnOnce(Handle<Input, Output, Tag>) -> Fut + 'static,
+    ) -> impl Future<Output = ChildRet>
+    where
+        ChildRet: 'static,
+        Fut: Future<Output = ChildRet> + 'static,
+    {
+        let holder = SharedCell::new();
+        let result = holder.clone();
+
+        let make: BoxSpawn<Input, Output, Tag> = Box::new(move |handle| {
+            Box::pin(async move {
+                let ret = child(handle).await;
+                result.set(ret);
+            })
+        });
+
+        self.spawner.borrow_mut().queue.push_back(make);
+
+        WantInput::new(holder)
+    }
 }
 
 #[derive(Default)]
@@ -86,57 +182,285 @@ impl<T> Future for WantInput<T> {
     }
 }
 
-struct Executor<Input, Output, Fut> {
-    future: Option<Pin<Box<Fut>>>,
-    inner: SharedCell<InnerEvent<Input, Output>>,
+/// The future behind `Handle::select_input`.
+///
+/// Each arm carries its own holder (filled by its own `Responder`) and a `cancelled` flag
+/// that gets set for every arm but the winner once one resolves.
+struct SelectInput<Input, Tag> {
+    arms: Vec<(Tag, SharedCell<Input>, Rc<Cell<bool>>)>,
+}
+
+impl<Input, Tag: Unpin> Future for SelectInput<Input, Tag> {
+    type Output = (Tag, Input);
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        let Some(winner) = this.arms.iter().position(|(_, holder, _)| holder.is_set()) else {
+            return Poll::Pending;
+        };
+
+        let (tag, holder, _) = this.arms.remove(winner);
+        let value = holder.take().expect("winning arm's holder to be set");
+
+        for (_, _, cancelled) in this.arms.drain(..) {
+            cancelled.set(true);
+        }
+
+        Poll::Ready((tag, value))
+    }
+}
+
+/// Blueprint-to-executor mailbox of not-yet-delivered [`InnerEvent`]s.
+///
+/// Used instead of a single-slot cell so that `select_input` can register several
+/// outstanding requests from the same poll; the executor drains it wholesale each time the
+/// blueprint goes `Pending`.
+struct RequestQueue<Input, Output, Tag>(Rc<RefCell<VecDeque<InnerEvent<Input, Output, Tag>>>>);
+
+impl<Input, Output, Tag> RequestQueue<Input, Output, Tag> {
+    fn new() -> Self {
+        Self(Rc::new(RefCell::new(VecDeque::new())))
+    }
+
+    fn push(&self, event: InnerEvent<Input, Output, Tag>) {
+        self.0.borrow_mut().push_back(event);
+    }
+
+    fn drain(&self) -> VecDeque<InnerEvent<Input, Output, Tag>> {
+        std::mem::take(&mut self.0.borrow_mut())
+    }
+
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+type BoxSpawn<Input, Output, Tag> =
+    Box<dyn FnOnce(Handle<Input, Output, Tag>) -> Pin<Box<dyn Future<Output = ()>>>>;
+
+/// Queue of not-yet-started `Handle::spawn` calls, shared by every `Handle` in a spawned
+/// tree so a child spawning a grandchild flattens into the same executor.
+struct Spawner<Input, Output, Tag> {
+    queue: VecDeque<BoxSpawn<Input, Output, Tag>>,
+}
+
+impl<Input, Output, Tag> Spawner<Input, Output, Tag> {
+    fn new() -> Self {
+        Self {
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+type SpawnerHandle<Input, Output, Tag> = Rc<RefCell<Spawner<Input, Output, Tag>>>;
+
+struct ChildTask<Input, Output, Tag> {
+    task_id: TaskId,
+    future: Option<Pin<Box<dyn Future<Output = ()>>>>,
+    inner: RequestQueue<Input, Output, Tag>,
+    blocked: Rc<Cell<bool>>,
     waker: Waker,
+    woken: Rc<Cell<bool>>,
 }
-impl<Input, Output, Fut: Future> Executor<Input, Output, Fut> {
-    fn new(future: Fut, inner: SharedCell<InnerEvent<Input, Output>>) -> Self {
+
+type TaggedEvent<Input, Output, Return, Tag> = (TaskId, Event<Input, Output, Return, Tag>);
+
+/// What happened when a spawned child was given one poll, via [`poll_child`].
+enum ChildPoll {
+    /// Was already finished before this call; nothing happened.
+    AlreadyDone,
+    /// Just resolved on this very poll; the parent can observe its join next round.
+    JustFinished,
+    /// Parked on a `wait_event` (either already, or mid-poll just now); nothing to report.
+    Blocked,
+    /// Still pending. `drained` says whether it produced any events this poll (already
+    /// appended to the caller's queue); `woken` says whether it's worth polling again
+    /// right away rather than idling.
+    Pending { drained: bool, woken: bool },
+}
+
+/// Polls one spawned child exactly once, appending any events it produced (tagged with
+/// its own `TaskId`) onto `out`. Shared by `Executor` and `Scheduler`, which differ only
+/// in what they do with the result: `Executor` retries the whole tree round-robin,
+/// `Scheduler` re-polls each task (including children) within the same step only while
+/// it keeps waking itself without producing events.
+fn poll_child<Input, Output, Return, Tag>(
+    child: &mut ChildTask<Input, Output, Tag>,
+    out: &mut impl Extend<(TaskId, Event<Input, Output, Return, Tag>)>,
+) -> ChildPoll {
+    if child.future.is_none() {
+        return ChildPoll::AlreadyDone;
+    }
+    if child.blocked.get() {
+        return ChildPoll::Blocked;
+    }
+
+    let future = child.future.as_mut().expect("checked above");
+    let mut cx = Context::from_waker(&child.waker);
+
+    match future.as_mut().poll(&mut cx) {
+        Poll::Ready(()) => {
+            // The wrapping future already stashed the return value in the join's holder
+            // before resolving; the parent gets a chance to observe it next round.
+            child.future = None;
+            ChildPoll::JustFinished
+        }
+        Poll::Pending => {
+            // The child may have parked itself on an event mid-poll (via `wait_event`);
+            // in that case there's nothing to drain yet.
+            if child.blocked.get() {
+                return ChildPoll::Blocked;
+            }
+            let drained = child.inner.drain();
+            let produced = !drained.is_empty();
+            out.extend(drained.into_iter().map(|e| (child.task_id, e.into())));
+            ChildPoll::Pending {
+                drained: produced,
+                woken: child.woken.take(),
+            }
+        }
+    }
+}
+
+struct Executor<Input, Output, Fut: Future, Tag = ()> {
+    root: Option<Pin<Box<Fut>>>,
+    root_inner: RequestQueue<Input, Output, Tag>,
+    waker: Waker,
+    woken: Rc<Cell<bool>>,
+    children: Vec<ChildTask<Input, Output, Tag>>,
+    spawner: SpawnerHandle<Input, Output, Tag>,
+    pending: VecDeque<TaggedEvent<Input, Output, Fut::Output, Tag>>,
+}
+impl<Input, Output, Fut: Future, Tag> Executor<Input, Output, Fut, Tag> {
+    fn new(
+        future: Fut,
+        inner: RequestQueue<Input, Output, Tag>,
+        spawner: SpawnerHandle<Input, Output, Tag>,
+    ) -> Self {
+        let (waker, woken) = flag_waker();
         Self {
-            future: Some(Box::pin(future)),
-            inner,
-            waker: fake_waker(),
+            root: Some(Box::pin(future)),
+            root_inner: inner,
+            waker,
+            woken,
+            children: Vec::new(),
+            spawner,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<Input: 'static, Output: 'static, Fut: Future, Tag: 'static> Executor<Input, Output, Fut, Tag> {
+    /// Turns any `Handle::spawn` calls queued since the last poll into `ChildTask`s.
+    fn admit_spawned(&mut self) {
+        while let Some(make) = self.spawner.borrow_mut().queue.pop_front() {
+            let task_id = TaskId(self.children.len() + 1);
+            let inner = RequestQueue::new();
+            let blocked = Rc::new(Cell::new(false));
+
+            let handle = Handle {
+                inner: inner.clone(),
+                task_id,
+                blocked: blocked.clone(),
+                events: Rc::new(RefCell::new(EventRegistry::default())),
+                spawner: self.spawner.clone(),
+            };
+
+            let (waker, woken) = flag_waker();
+            self.children.push(ChildTask {
+                task_id,
+                future: Some(make(handle)),
+                inner,
+                blocked,
+                waker,
+                woken,
+            });
         }
     }
 }
 
-impl<I: 'static, O: 'static, Fut: Future> Iterator for Executor<I, O, Fut> {
-    type Item = Event<I, O, Fut::Output>;
+impl<Input: 'static, Output: 'static, Fut: Future, Tag: 'static> Iterator
+    for Executor<Input, Output, Fut, Tag>
+{
+    type Item = (TaskId, Event<Input, Output, Fut::Output, Tag>);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let future = self.future.as_mut()?;
-        let mut cx = Context::from_waker(&self.waker);
-
-        match Pin::new(future).poll(&mut cx) {
-            Poll::Ready(v) => {
-                // Any more calls to next() will yield None.
-                self.future = None;
-                Some(Event::Return(v))
+        if let Some(event) = self.pending.pop_front() {
+            return Some(event);
+        }
+
+        loop {
+            self.admit_spawned();
+
+            let root = self.root.as_mut()?;
+            let mut cx = Context::from_waker(&self.waker);
+            let mut woken_this_round = false;
+
+            match Pin::new(root).poll(&mut cx) {
+                Poll::Ready(v) => {
+                    // Any more calls to next() will yield None; unfinished children are
+                    // simply dropped along with the rest of the executor.
+                    self.root = None;
+                    return Some((TaskId::default(), Event::Return(v)));
+                }
+                Poll::Pending => {
+                    let drained = self.root_inner.drain();
+                    self.pending
+                        .extend(drained.into_iter().map(|e| (TaskId::default(), e.into())));
+                    if self.woken.take() {
+                        woken_this_round = true;
+                    }
+                }
             }
-            Poll::Pending => {
-                let inner_event = self.inner.0.replace(None).expect("inner event");
-                Some(inner_event.into())
+
+            for child in self.children.iter_mut() {
+                match poll_child(child, &mut self.pending) {
+                    ChildPoll::JustFinished => woken_this_round = true,
+                    ChildPoll::Pending { woken: true, .. } => woken_this_round = true,
+                    ChildPoll::AlreadyDone
+                    | ChildPoll::Pending { woken: false, .. }
+                    | ChildPoll::Blocked => {}
+                }
             }
+
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+
+            // Nothing queued this round. If anything woke itself (a timer, a sub-future
+            // making progress, a child just finishing) it's worth polling again right
+            // away; otherwise the whole tree is genuinely idle.
+            if woken_this_round {
+                continue;
+            }
+            return Some((TaskId::default(), Event::Idle));
         }
     }
 }
 
-pub enum Event<Input, Output, Return> {
-    Input(Responder<Input>),
+pub enum Event<Input, Output, Return, Tag = ()> {
+    /// A `tag` identifies which `want_input_tagged` call this request came from, so a
+    /// driver facing several distinct kinds of input can tell them apart.
+    Input { tag: Tag, responder: Responder<Input> },
     Output(Output),
+    /// The blueprint is pending on something other than `want_input`/`provide_output`
+    /// (a timer, a join, a combinator) and made no further progress this poll.
+    Idle,
     Return(Return),
 }
 
-enum InnerEvent<Input, Output> {
-    Input(Responder<Input>),
+enum InnerEvent<Input, Output, Tag = ()> {
+    Input { tag: Tag, responder: Responder<Input> },
     Output(Output),
 }
 
-impl<Input, Output, Return> Into<Event<Input, Output, Return>> for InnerEvent<Input, Output> {
-    fn into(self) -> Event<Input, Output, Return> {
+impl<Input, Output, Return, Tag> Into<Event<Input, Output, Return, Tag>>
+    for InnerEvent<Input, Output, Tag>
+{
+    fn into(self) -> Event<Input, Output, Return, Tag> {
         match self {
-            InnerEvent::Input(i) => Event::Input(i),
+            InnerEvent::Input { tag, responder } => Event::Input { tag, responder },
             InnerEvent::Output(o) => Event::Output(o),
         }
     }
@@ -144,14 +468,35 @@ impl<Input, Output, Return> Into<Event<Input, Output, Return>> for InnerEvent<In
 
 pub struct Responder<T = ()> {
     holder: SharedCell<T>,
+    /// Set by a `select_input` future for every arm but the one that won, so a late
+    /// `provide` on a cancelled arm is silently dropped instead of racing the winner.
+    cancelled: Option<Rc<Cell<bool>>>,
 }
 
 impl<T> Responder<T> {
     fn new(holder: SharedCell<T>) -> Self {
-        Self { holder }
+        Self {
+            holder,
+            cancelled: None,
+        }
+    }
+
+    fn new_cancellable(holder: SharedCell<T>, cancelled: Rc<Cell<bool>>) -> Self {
+        Self {
+            holder,
+            cancelled: Some(cancelled),
+        }
+    }
+
+    /// Whether this request lost a `select_input` race and no longer needs an answer.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.as_ref().is_some_and(|c| c.get())
     }
 
     pub fn provide(self, data: T) {
+        if self.is_cancelled() {
+            return;
+        }
         self.holder.set(data);
     }
 }
@@ -178,6 +523,14 @@ impl<T> SharedCell<T> {
         self.0.replace(None)
     }
 
+    /// Checks whether a value is present without consuming it.
+    fn is_set(&self) -> bool {
+        let value = self.0.take();
+        let is_set = value.is_some();
+        self.0.set(value);
+        is_set
+    }
+
     fn clone(&self) -> Self {
         SharedCell(self.0.clone())
     }
@@ -253,11 +606,11 @@ mod test {
     fn test() {
         let events = run(test_blauprint);
 
-        for io in events {
+        for (_, io) in events {
             match io {
-                Event::Input(res) => {
+                Event::Input { responder, .. } => {
                     println!("provide input");
-                    res.provide(42);
+                    responder.provide(42);
                 }
                 Event::Output(out) => {
                     println!("output: {}", out);
@@ -267,7 +620,125 @@ mod test {
                     println!("end");
                     assert_eq!(end, "alles gut");
                 }
+                Event::Idle => unreachable!("this blueprint never idles"),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum InputKind {
+        Credentials,
+        TimeoutAck,
+    }
+
+    async fn tagged_blauprint(handle: Handle<&'static str, (), InputKind>) -> &'static str {
+        let creds = handle.want_input_tagged(InputKind::Credentials).await;
+        assert_eq!(creds, "secret");
+
+        let ack = handle.want_input_tagged(InputKind::TimeoutAck).await;
+        assert_eq!(ack, "ack");
+
+        "done"
+    }
+
+    #[test]
+    fn want_input_tagged_lets_driver_tell_requests_apart() {
+        let mut seen = Vec::new();
+
+        for (_, io) in run(tagged_blauprint) {
+            match io {
+                Event::Input { tag, responder } => {
+                    let reply = match tag {
+                        InputKind::Credentials => "secret",
+                        InputKind::TimeoutAck => "ack",
+                    };
+                    seen.push(tag);
+                    responder.provide(reply);
+                }
+                Event::Return(end) => assert_eq!(end, "done"),
+                Event::Output(_) | Event::Idle => unreachable!(),
+            }
+        }
+
+        assert_eq!(seen, vec![InputKind::Credentials, InputKind::TimeoutAck]);
+    }
+
+    async fn select_blauprint(handle: Handle<&'static str, (), InputKind>) -> &'static str {
+        let (which, value) = handle
+            .select_input([InputKind::Credentials, InputKind::TimeoutAck])
+            .await;
+        assert_eq!(which, InputKind::Credentials);
+        value
+    }
+
+    #[test]
+    fn select_input_resolves_on_first_answer_and_cancels_the_rest() {
+        let mut events = run(select_blauprint);
+
+        // Both arms are requested up front.
+        let mut responders = Vec::new();
+        for _ in 0..2 {
+            match events.next().unwrap().1 {
+                Event::Input { tag, responder } => responders.push((tag, responder)),
+                _ => panic!("expected two Event::Input requests up front"),
             }
         }
+
+        let (_, creds_responder) = responders
+            .into_iter()
+            .find(|(tag, _)| *tag == InputKind::Credentials)
+            .expect("a Credentials request");
+        creds_responder.provide("secret");
+
+        loop {
+            match events.next().unwrap().1 {
+                Event::Return(end) => {
+                    assert_eq!(end, "secret");
+                    break;
+                }
+                Event::Idle => continue,
+                _ => panic!("unexpected event"),
+            }
+        }
+    }
+
+    async fn parent_with_child(handle: Handle<&'static str, (), InputKind>) -> &'static str {
+        let child = handle.spawn(|child_handle: Handle<&'static str, (), InputKind>| async move {
+            let input = child_handle.want_input_tagged(InputKind::Credentials).await;
+            assert_eq!(input, "child secret");
+            "child done"
+        });
+
+        let parent_input = handle.want_input_tagged(InputKind::TimeoutAck).await;
+        assert_eq!(parent_input, "parent secret");
+
+        let child_result = child.await;
+        assert_eq!(child_result, "child done");
+
+        "parent done"
+    }
+
+    #[test]
+    fn spawn_interleaves_child_events_tagged_with_its_task_id() {
+        let mut child_task_id = None;
+
+        for (task_id, io) in run(parent_with_child) {
+            match io {
+                Event::Input { tag, responder } => {
+                    if task_id == TaskId::default() {
+                        assert_eq!(tag, InputKind::TimeoutAck);
+                        responder.provide("parent secret");
+                    } else {
+                        child_task_id = Some(task_id);
+                        assert_eq!(tag, InputKind::Credentials);
+                        responder.provide("child secret");
+                    }
+                }
+                Event::Return(end) => assert_eq!(end, "parent done"),
+                Event::Output(_) | Event::Idle => unreachable!(),
+            }
+        }
+
+        assert!(child_task_id.is_some_and(|id| id != TaskId::default()));
     }
 }
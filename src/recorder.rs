@@ -0,0 +1,308 @@
+use std::collections::VecDeque;
+use std::future::Future;
+
+use crate::{run, Event, Handle, Responder, TaskId};
+
+/// The ordered log of everything exchanged with a blueprint over one `run`: every input
+/// request's outcome, every output emitted, and the final return value.
+///
+/// An entry in `inputs` is `Some(value)` for a request that was answered, or `None` for
+/// one that was left unanswered (e.g. a `select_input` arm that lost the race) — see
+/// [`Recorder::cancel`]. Entries are in the order those calls were made; [`replay`] hands
+/// them out in the order the blueprint's `Event::Input` requests are issued, without
+/// looking at tags, so each outcome reaches the right request only if the driver answered
+/// the requests in that same order.
+///
+/// Capture one via [`Recorder`], then feed it back through [`replay`] to deterministically
+/// re-run the same blueprint and assert it behaves the same way. Fields are plain data so
+/// callers can serialize a `Transcript` however they like; this crate has no opinion on
+/// the format.
+#[derive(Debug, Clone)]
+pub struct Transcript<Input, Output, Return> {
+    pub inputs: VecDeque<Option<Input>>,
+    pub outputs: VecDeque<Output>,
+    pub ret: Option<Return>,
+}
+
+impl<Input, Output, Return> Transcript<Input, Output, Return> {
+    fn new() -> Self {
+        Self {
+            inputs: VecDeque::new(),
+            outputs: VecDeque::new(),
+            ret: None,
+        }
+    }
+}
+
+impl<Input, Output, Return> Default for Transcript<Input, Output, Return> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Wraps the iterator returned by [`run`], capturing a [`Transcript`] as the blueprint is
+/// driven live.
+///
+/// Drive a `Recorder` exactly like `run`'s iterator, with one difference: for every
+/// `Event::Input` it yields, call either [`Recorder::provide`] (instead of
+/// `responder.provide` directly) or, if the request is being left unanswered (e.g. a
+/// `select_input` arm that isn't the one being answered), [`Recorder::cancel`]. Either way
+/// the outcome gets logged, in order, so [`replay`] can tell answered requests apart from
+/// skipped ones without needing to know their tag. Once the blueprint returns (or at any
+/// earlier point), call [`Recorder::into_transcript`] to get the captured log.
+pub struct Recorder<Input, Output, Return, Tag, It>
+where
+    It: Iterator<Item = (TaskId, Event<Input, Output, Return, Tag>)>,
+{
+    events: It,
+    transcript: Transcript<Input, Output, Return>,
+}
+
+impl<Input, Output, Return, Tag, It> Recorder<Input, Output, Return, Tag, It>
+where
+    It: Iterator<Item = (TaskId, Event<Input, Output, Return, Tag>)>,
+{
+    pub fn new(events: It) -> Self {
+        Self {
+            events,
+            transcript: Transcript::new(),
+        }
+    }
+
+    /// Feeds `value` to `responder`, exactly like `responder.provide(value)`, and also
+    /// appends it to the transcript's input log.
+    pub fn provide(&mut self, responder: Responder<Input>, value: Input)
+    where
+        Input: Clone,
+    {
+        self.transcript.inputs.push_back(Some(value.clone()));
+        responder.provide(value);
+    }
+
+    /// Leaves `responder` unanswered (e.g. a `select_input` arm that lost the race),
+    /// recording its outcome as "no answer" so [`replay`] knows not to expect one.
+    pub fn cancel(&mut self, responder: Responder<Input>) {
+        let _ = responder;
+        self.transcript.inputs.push_back(None);
+    }
+
+    /// Consumes the recorder, returning everything captured so far.
+    pub fn into_transcript(self) -> Transcript<Input, Output, Return> {
+        self.transcript
+    }
+}
+
+impl<Output, Return, Tag, It> Recorder<(), Output, Return, Tag, It>
+where
+    It: Iterator<Item = (TaskId, Event<(), Output, Return, Tag>)>,
+{
+    /// Shorthand for `self.provide(responder, ())`, mirroring `Responder::resume`.
+    pub fn resume(&mut self, responder: Responder<()>) {
+        self.provide(responder, ());
+    }
+}
+
+impl<Input, Output, Return, Tag, It> Iterator for Recorder<Input, Output, Return, Tag, It>
+where
+    Output: Clone,
+    Return: Clone,
+    It: Iterator<Item = (TaskId, Event<Input, Output, Return, Tag>)>,
+{
+    type Item = (TaskId, Event<Input, Output, Return, Tag>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (task, event) = self.events.next()?;
+
+        match &event {
+            Event::Output(output) => {
+                self.transcript.outputs.push_back(output.clone());
+            }
+            Event::Return(ret) => {
+                self.transcript.ret = Some(ret.clone());
+            }
+            Event::Input { .. } | Event::Idle => {}
+        }
+
+        Some((task, event))
+    }
+}
+
+/// Something observed during [`replay`] that doesn't match the recorded [`Transcript`].
+#[derive(Debug)]
+pub enum ReplayError<Output, Return> {
+    /// The blueprint asked for another input, but the transcript had none left.
+    InputExhausted,
+    /// The blueprint produced an output after the transcript's output log ran out.
+    OutputExhausted(Output),
+    /// An emitted output doesn't match the next one recorded.
+    OutputMismatch { expected: Output, actual: Output },
+    /// The blueprint returned before emitting all the outputs the transcript recorded.
+    OutputsRemaining(usize),
+    /// The blueprint returned before consuming all the inputs the transcript recorded.
+    InputsRemaining(usize),
+    /// The transcript has no recorded return value to compare against.
+    ReturnMissing(Return),
+    /// The blueprint's return value doesn't match the one recorded.
+    ReturnMismatch { expected: Return, actual: Return },
+}
+
+/// Re-runs `blueprint`, feeding back the inputs recorded in `transcript` and asserting
+/// every emitted output and the final return match what was recorded.
+///
+/// Returns `Ok(())` if the whole transcript replays faithfully, or the first divergence
+/// encountered as a [`ReplayError`].
+pub fn replay<Input, Output, Tag, Fut>(
+    blueprint: impl FnOnce(Handle<Input, Output, Tag>) -> Fut,
+    mut transcript: Transcript<Input, Output, Fut::Output>,
+) -> Result<(), ReplayError<Output, Fut::Output>>
+where
+    Input: 'static,
+    Output: 'static + PartialEq,
+    Tag: 'static,
+    Fut: Future + 'static,
+    Fut::Output: PartialEq,
+{
+    for (_, event) in run(blueprint) {
+        match event {
+            Event::Input { responder, .. } => {
+                let Some(outcome) = transcript.inputs.pop_front() else {
+                    return Err(ReplayError::InputExhausted);
+                };
+                // `None` means this one was recorded as left unanswered (e.g. a
+                // `select_input` arm that lost the race); replaying it the same way
+                // means not providing it either.
+                if let Some(value) = outcome {
+                    responder.provide(value);
+                }
+            }
+            Event::Output(actual) => match transcript.outputs.pop_front() {
+                Some(expected) if expected == actual => {}
+                Some(expected) => return Err(ReplayError::OutputMismatch { expected, actual }),
+                None => return Err(ReplayError::OutputExhausted(actual)),
+            },
+            Event::Idle => {}
+            Event::Return(actual) => {
+                if !transcript.outputs.is_empty() {
+                    return Err(ReplayError::OutputsRemaining(transcript.outputs.len()));
+                }
+                if !transcript.inputs.is_empty() {
+                    return Err(ReplayError::InputsRemaining(transcript.inputs.len()));
+                }
+                return match transcript.ret {
+                    Some(expected) if expected == actual => Ok(()),
+                    Some(expected) => Err(ReplayError::ReturnMismatch { expected, actual }),
+                    None => Err(ReplayError::ReturnMissing(actual)),
+                };
+            }
+        }
+    }
+
+    unreachable!("run()'s iterator always yields an Event::Return before ending")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::run;
+
+    async fn echo_blauprint(handle: Handle<u8, u8>) -> &'static str {
+        for _ in 0..2 {
+            let input = handle.want_input().await;
+            handle.provide_output(input * 2).await;
+        }
+        "done"
+    }
+
+    fn record(next_input: u8) -> Transcript<u8, u8, &'static str> {
+        let mut recorder = Recorder::new(run(echo_blauprint));
+        let mut input = next_input;
+
+        loop {
+            match recorder.next().unwrap().1 {
+                Event::Input { responder, .. } => {
+                    recorder.provide(responder, input);
+                    input += 1;
+                }
+                Event::Output(_) => {}
+                Event::Return(_) => break,
+                Event::Idle => unreachable!(),
+            }
+        }
+
+        recorder.into_transcript()
+    }
+
+    #[test]
+    fn replay_accepts_a_faithful_transcript() {
+        let transcript = record(10);
+        assert!(replay(echo_blauprint, transcript).is_ok());
+    }
+
+    #[test]
+    fn replay_rejects_a_tampered_output_log() {
+        let mut transcript = record(10);
+        transcript.outputs[0] = 0;
+
+        let err = replay(echo_blauprint, transcript).unwrap_err();
+        assert!(matches!(err, ReplayError::OutputMismatch { expected: 0, actual: 20 }));
+    }
+
+    #[test]
+    fn replay_rejects_an_exhausted_input_log() {
+        let mut transcript = record(10);
+        transcript.inputs.pop_back();
+
+        let err = replay(echo_blauprint, transcript).unwrap_err();
+        assert!(matches!(err, ReplayError::InputExhausted));
+    }
+
+    #[test]
+    fn replay_rejects_a_transcript_with_an_unconsumed_input() {
+        let mut transcript = record(10);
+        transcript.inputs.push_back(Some(99));
+
+        let err = replay(echo_blauprint, transcript).unwrap_err();
+        assert!(matches!(err, ReplayError::InputsRemaining(1)));
+    }
+
+    async fn select_blauprint(handle: Handle<u8, (), &'static str>) -> u8 {
+        let (_, value) = handle.select_input(["a", "b"]).await;
+        value
+    }
+
+    #[test]
+    fn replay_accepts_a_select_input_transcript_with_a_skipped_arm() {
+        let mut recorder = Recorder::new(run(select_blauprint));
+        let mut responders = Vec::new();
+
+        for _ in 0..2 {
+            match recorder.next().unwrap().1 {
+                Event::Input { responder, .. } => responders.push(responder),
+                _ => panic!("expected two Event::Input requests up front"),
+            }
+        }
+
+        // Answer the first arm and explicitly skip the second, exactly like a real driver
+        // leaving a losing `select_input` arm unanswered — in the same order the
+        // requests were issued, so the transcript lines up with `replay`'s own ordering.
+        let mut responders = responders.into_iter();
+        let first = responders.next().unwrap();
+        let second = responders.next().unwrap();
+        recorder.provide(first, 7);
+        recorder.cancel(second);
+
+        loop {
+            match recorder.next().unwrap().1 {
+                Event::Return(v) => {
+                    assert_eq!(v, 7);
+                    break;
+                }
+                Event::Idle => {}
+                _ => panic!("unexpected event"),
+            }
+        }
+
+        let transcript = recorder.into_transcript();
+        assert!(replay(select_blauprint, transcript).is_ok());
+    }
+}
@@ -0,0 +1,443 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use crate::waker::flag_waker;
+use crate::{poll_child, ChildPoll, ChildTask, Event, Handle, RequestQueue, Spawner, SpawnerHandle};
+
+/// A token identifying one of the wake events created by [`Scheduler::create_event`].
+///
+/// Handed to a blueprint so it can park on the event via `Handle::wait_event`, and to
+/// external code so it can fire the event via [`Scheduler::notify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EventHandle(usize);
+
+/// Identifies one of the blueprints running inside a [`Scheduler`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TaskId(pub(crate) usize);
+
+impl Default for TaskId {
+    /// The id of the root blueprint outside of a `Scheduler` (e.g. via [`crate::run`]);
+    /// children it spawns there get ids counting up from 1.
+    fn default() -> Self {
+        TaskId(0)
+    }
+}
+
+/// Tracks which tasks are parked on which [`EventHandle`]s.
+///
+/// Shared between the `Scheduler` and every `Handle` it hands out, so a blueprint can
+/// register itself as parked without going back through the scheduler.
+#[derive(Default)]
+pub(crate) struct EventRegistry {
+    next_event: usize,
+    parked: HashMap<usize, HashSet<usize>>,
+}
+
+struct Task<Input, Output, Ret, Tag = ()> {
+    future: Option<Pin<Box<dyn Future<Output = Ret>>>>,
+    inner: RequestQueue<Input, Output, Tag>,
+    blocked: Rc<Cell<bool>>,
+    waker: Waker,
+    woken: Rc<Cell<bool>>,
+}
+
+/// Runs many blueprints cooperatively, one `step()` at a time.
+///
+/// Unlike [`crate::run`], which drives a single future to completion, a `Scheduler` owns
+/// a collection of blueprints (e.g. several protocol state machines) and polls every
+/// runnable one of them on each `step()` call. Blueprints can park themselves on an
+/// [`EventHandle`] via `Handle::wait_event` and be woken again by [`Scheduler::notify`].
+pub struct Scheduler<Input, Output, Ret, Tag = ()> {
+    tasks: Vec<Task<Input, Output, Ret, Tag>>,
+    /// Children spawned (possibly transitively) from any task via `Handle::spawn`, shared
+    /// across every task's `Handle` so a nested spawn flattens into this same pool.
+    children: Vec<ChildTask<Input, Output, Tag>>,
+    spawner: SpawnerHandle<Input, Output, Tag>,
+    events: Rc<RefCell<EventRegistry>>,
+}
+
+impl<Input: 'static, Output: 'static, Ret: 'static, Tag: 'static> Scheduler<Input, Output, Ret, Tag> {
+    pub fn new() -> Self {
+        Self {
+            tasks: Vec::new(),
+            children: Vec::new(),
+            spawner: Rc::new(RefCell::new(Spawner::new())),
+            events: Rc::new(RefCell::new(EventRegistry::default())),
+        }
+    }
+
+    /// Adds a blueprint to the scheduler and returns the [`TaskId`] it was assigned.
+    ///
+    /// The task is not polled until the next call to [`Scheduler::step`].
+    pub fn add_blueprint<Fut>(
+        &mut self,
+        blueprint: impl FnOnce(Handle<Input, Output, Tag>) -> Fut,
+    ) -> TaskId
+    where
+        Fut: Future<Output = Ret> + 'static,
+    {
+        let task_id = TaskId(self.tasks.len());
+
+        let inner = RequestQueue::new();
+        let blocked = Rc::new(Cell::new(false));
+
+        let handle = Handle {
+            inner: inner.clone(),
+            task_id,
+            blocked: blocked.clone(),
+            events: self.events.clone(),
+            spawner: self.spawner.clone(),
+        };
+
+        let future = Box::pin(blueprint(handle));
+        let (waker, woken) = flag_waker();
+
+        self.tasks.push(Task {
+            future: Some(future),
+            inner,
+            blocked,
+            waker,
+            woken,
+        });
+
+        task_id
+    }
+
+    /// Creates a new wake event that blueprints can park on via `Handle::wait_event`.
+    pub fn create_event(&mut self) -> EventHandle {
+        let mut events = self.events.borrow_mut();
+        let id = events.next_event;
+        events.next_event += 1;
+        EventHandle(id)
+    }
+
+    /// Marks every task parked on `handle` as runnable again.
+    ///
+    /// The tasks are re-polled on the next call to [`Scheduler::step`]; `notify` itself
+    /// does not poll anything. Looks at both top-level tasks and spawned children, since
+    /// `Handle::wait_event` is available on either.
+    pub fn notify(&mut self, handle: EventHandle) {
+        let Some(parked) = self.events.borrow_mut().parked.remove(&handle.0) else {
+            return;
+        };
+
+        for task_id in parked {
+            if let Some(task) = self.tasks.get(task_id) {
+                task.blocked.set(false);
+            } else if let Some(child) = self.children.iter().find(|c| c.task_id.0 == task_id) {
+                child.blocked.set(false);
+            }
+        }
+    }
+
+    /// Turns any `Handle::spawn` calls queued since the last step into `ChildTask`s.
+    ///
+    /// Children get ids counted down from `usize::MAX` so they can never collide with a
+    /// top-level task's index-based id.
+    fn admit_spawned(&mut self) {
+        while let Some(make) = self.spawner.borrow_mut().queue.pop_front() {
+            let task_id = TaskId(usize::MAX - self.children.len());
+            let inner = RequestQueue::new();
+            let blocked = Rc::new(Cell::new(false));
+
+            let handle = Handle {
+                inner: inner.clone(),
+                task_id,
+                blocked: blocked.clone(),
+                events: self.events.clone(),
+                spawner: self.spawner.clone(),
+            };
+
+            let (waker, woken) = flag_waker();
+            self.children.push(ChildTask {
+                task_id,
+                future: Some(make(handle)),
+                inner,
+                blocked,
+                waker,
+                woken,
+            });
+        }
+    }
+
+    /// Polls every non-blocked task, draining the events each produced.
+    ///
+    /// A task is polled once, unless a poll leaves it pending with no events queued and
+    /// its waker fired during that poll; then it is polled again within this same step,
+    /// until it produces events, parks, finishes or stops waking itself.
+    ///
+    /// This includes any children spawned via `Handle::spawn`, whose own `Input`/`Output`
+    /// events interleave tagged with their own `TaskId`; a child task's completion just
+    /// resolves its join future and isn't surfaced as an `Event::Return` of its own.
+    pub fn step(&mut self) -> Vec<(TaskId, Event<Input, Output, Ret, Tag>)> {
+        let mut out = Vec::new();
+
+        self.admit_spawned();
+
+        for (i, task) in self.tasks.iter_mut().enumerate() {
+            let task_id = TaskId(i);
+
+            if task.blocked.get() {
+                continue;
+            }
+
+            while let Some(future) = task.future.as_mut() {
+                let mut cx = Context::from_waker(&task.waker);
+
+                match future.as_mut().poll(&mut cx) {
+                    Poll::Ready(v) => {
+                        task.future = None;
+                        out.push((task_id, Event::Return(v)));
+                        break;
+                    }
+                    Poll::Pending => {
+                        // The blueprint may have parked itself on an event mid-poll (via
+                        // `wait_event`); in that case there's nothing to report yet.
+                        if task.blocked.get() {
+                            break;
+                        }
+                        let drained = task.inner.drain();
+                        if !drained.is_empty() {
+                            out.extend(drained.into_iter().map(|e| (task_id, e.into())));
+                            break;
+                        }
+                        // Pending with nothing queued: if the future woke itself, it's
+                        // worth polling again within this same step; otherwise it's
+                        // genuinely idle this round.
+                        if task.woken.take() {
+                            continue;
+                        }
+                        out.push((task_id, Event::Idle));
+                        break;
+                    }
+                }
+            }
+        }
+
+        for child in self.children.iter_mut() {
+            let task_id = child.task_id;
+
+            loop {
+                match poll_child(child, &mut out) {
+                    ChildPoll::AlreadyDone | ChildPoll::JustFinished | ChildPoll::Blocked => break,
+                    ChildPoll::Pending { drained: true, .. } => break,
+                    ChildPoll::Pending { drained: false, woken: true } => continue,
+                    ChildPoll::Pending { drained: false, woken: false } => {
+                        out.push((task_id, Event::Idle));
+                        break;
+                    }
+                }
+            }
+        }
+
+        out
+    }
+}
+
+impl<Input: 'static, Output: 'static, Ret: 'static, Tag: 'static> Default
+    for Scheduler<Input, Output, Ret, Tag>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub(crate) struct WaitEvent {
+    task_id: TaskId,
+    event_id: usize,
+    events: Rc<RefCell<EventRegistry>>,
+    blocked: Rc<Cell<bool>>,
+    registered: bool,
+}
+
+impl WaitEvent {
+    pub(crate) fn new(
+        task_id: TaskId,
+        handle: EventHandle,
+        events: Rc<RefCell<EventRegistry>>,
+        blocked: Rc<Cell<bool>>,
+    ) -> Self {
+        Self {
+            task_id,
+            event_id: handle.0,
+            events,
+            blocked,
+            registered: false,
+        }
+    }
+}
+
+impl Future for WaitEvent {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if !self.registered {
+            self.events
+                .borrow_mut()
+                .parked
+                .entry(self.event_id)
+                .or_default()
+                .insert(self.task_id.0);
+            self.blocked.set(true);
+            self.registered = true;
+            return Poll::Pending;
+        }
+
+        if self.blocked.get() {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Event;
+
+    #[test]
+    fn notify_wakes_parked_tasks() {
+        let mut sched: Scheduler<u8, String, &'static str> = Scheduler::new();
+        let ev = sched.create_event();
+
+        sched.add_blueprint(move |handle| async move {
+            handle.wait_event(ev).await;
+            "woken"
+        });
+
+        assert!(sched.step().is_empty());
+
+        sched.notify(ev);
+
+        let events = sched.step();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0].1, Event::Return("woken")));
+    }
+
+    #[test]
+    fn step_polls_every_runnable_task_once() {
+        let mut sched: Scheduler<u8, String, u8> = Scheduler::new();
+
+        sched.add_blueprint(|handle| async move { handle.want_input().await });
+        sched.add_blueprint(|handle| async move { handle.want_input().await + 1 });
+
+        let events = sched.step();
+        assert_eq!(events.len(), 2);
+
+        for (_, event) in events {
+            match event {
+                Event::Input { responder, .. } => responder.provide(41),
+                _ => panic!("expected Event::Input, got a different event"),
+            }
+        }
+
+        let events = sched.step();
+        let mut returns: Vec<u8> = events
+            .into_iter()
+            .map(|(_, event)| match event {
+                Event::Return(v) => v,
+                _ => panic!("expected Event::Return"),
+            })
+            .collect();
+        returns.sort();
+
+        assert_eq!(returns, vec![41, 42]);
+    }
+
+    #[test]
+    fn spawn_is_polled_and_joined_within_a_scheduler() {
+        let mut sched: Scheduler<u8, String, u8> = Scheduler::new();
+
+        sched.add_blueprint(|handle| async move {
+            let child = handle.spawn(|child_handle: Handle<u8, String>| async move {
+                child_handle.want_input().await + 1
+            });
+            child.await
+        });
+
+        // The child isn't admitted until the step after `spawn` was called, so the first
+        // step or two may just report the parent idling on the join.
+        let mut responder = None;
+        for _ in 0..10 {
+            for (task_id, event) in sched.step() {
+                match event {
+                    Event::Input { responder: r, .. } => {
+                        assert_ne!(task_id, TaskId(0), "the input should come from the child");
+                        responder = Some(r);
+                    }
+                    Event::Idle => {}
+                    _ => panic!("unexpected event before the child asked for input"),
+                }
+            }
+            if responder.is_some() {
+                break;
+            }
+        }
+        responder.expect("child's input request").provide(41);
+
+        let mut ret = None;
+        for _ in 0..10 {
+            for (task_id, event) in sched.step() {
+                match event {
+                    Event::Return(v) => {
+                        assert_eq!(task_id, TaskId(0), "the return should come from the parent");
+                        ret = Some(v);
+                    }
+                    Event::Idle => {}
+                    _ => panic!("unexpected event after answering the child"),
+                }
+            }
+            if ret.is_some() {
+                break;
+            }
+        }
+        assert_eq!(ret, Some(42));
+    }
+
+    #[test]
+    fn notify_wakes_a_child_parked_with_wait_event() {
+        let mut sched: Scheduler<u8, String, u8> = Scheduler::new();
+        let ev = sched.create_event();
+
+        sched.add_blueprint(move |handle| async move {
+            let child = handle.spawn(move |child_handle: Handle<u8, String>| async move {
+                child_handle.wait_event(ev).await;
+                41
+            });
+            child.await
+        });
+
+        // A couple of steps are enough to admit the child and let it park on the event;
+        // a parked child reports nothing of its own, so there's no event to wait for here.
+        for _ in 0..3 {
+            for (_, event) in sched.step() {
+                assert!(matches!(event, Event::Idle), "nothing but the parent idling yet");
+            }
+        }
+
+        sched.notify(ev);
+
+        let mut ret = None;
+        for _ in 0..10 {
+            for (task_id, event) in sched.step() {
+                match event {
+                    Event::Return(v) => {
+                        assert_eq!(task_id, TaskId(0), "the return should come from the parent");
+                        ret = Some(v);
+                    }
+                    Event::Idle => {}
+                    _ => panic!("unexpected event after notifying the child"),
+                }
+            }
+            if ret.is_some() {
+                break;
+            }
+        }
+        assert_eq!(ret, Some(41));
+    }
+}
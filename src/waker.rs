@@ -1,19 +1,39 @@
-use std::ptr;
+use std::cell::Cell;
+use std::rc::Rc;
 use std::task::{RawWaker, RawWakerVTable, Waker};
 
-pub fn fake_waker() -> Waker {
-    let waker = RawWaker::new(ptr::null(), &RAW_WAKER_VTABLE);
-    unsafe { Waker::from_raw(waker) }
+/// Creates a [`Waker`] backed by an `Rc<Cell<bool>>` "woken" flag.
+///
+/// `wake`/`wake_by_ref` set the flag, `clone` clones the `Rc`. Returns the waker together
+/// with the flag it sets, so an executor can check and reset it between polls to decide
+/// whether a `Pending` future is worth re-polling right away.
+pub fn flag_waker() -> (Waker, Rc<Cell<bool>>) {
+    let flag = Rc::new(Cell::new(false));
+    let ptr = Rc::into_raw(flag.clone()) as *const ();
+    let waker = unsafe { Waker::from_raw(RawWaker::new(ptr, &RAW_WAKER_VTABLE)) };
+    (waker, flag)
 }
 
-const RAW_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
+const RAW_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop_flag);
 
-fn clone(_ptr: *const ()) -> RawWaker {
-    panic!("Blueprint is not an async runtime.");
+unsafe fn clone(ptr: *const ()) -> RawWaker {
+    let flag = Rc::from_raw(ptr as *const Cell<bool>);
+    let cloned = flag.clone();
+    std::mem::forget(flag);
+    RawWaker::new(Rc::into_raw(cloned) as *const (), &RAW_WAKER_VTABLE)
 }
 
-fn wake(_ptr: *const ()) {}
+unsafe fn wake(ptr: *const ()) {
+    let flag = Rc::from_raw(ptr as *const Cell<bool>);
+    flag.set(true);
+}
 
-fn wake_by_ref(_ptr: *const ()) {}
+unsafe fn wake_by_ref(ptr: *const ()) {
+    let flag = Rc::from_raw(ptr as *const Cell<bool>);
+    flag.set(true);
+    std::mem::forget(flag);
+}
 
-fn drop(_ptr: *const ()) {}
+unsafe fn drop_flag(ptr: *const ()) {
+    drop(Rc::from_raw(ptr as *const Cell<bool>));
+}